@@ -1,20 +1,21 @@
 //! Asynchronous TLS/SSL streams for Tokio using [Rustls](https://github.com/ctz/rustls).
 
 
-#[cfg_attr(feature = "tokio-proto", macro_use)] extern crate futures;
+extern crate futures;
 #[macro_use] extern crate tokio_io;
 extern crate rustls;
 extern crate webpki;
 
-pub mod proto;
-
 use std::io;
+#[cfg(feature = "early-data")]
+use std::mem;
 use std::sync::Arc;
 use futures::{ Future, Poll, Async };
 use tokio_io::{ AsyncRead, AsyncWrite };
 use rustls::{
     Session, ClientSession, ServerSession,
-    ClientConfig, ServerConfig
+    ClientConfig, ServerConfig,
+    Certificate, ProtocolVersion
 };
 use webpki::DNSNameRef;
 
@@ -24,6 +25,15 @@ pub trait ClientConfigExt {
     fn connect_async<S>(&self, domain: DNSNameRef, stream: S)
         -> ConnectAsync<S>
         where S: AsyncRead + AsyncWrite;
+
+    /// Like `connect_async`, but if `domain` has a resumable session ticket
+    /// that permits it, application data written before the handshake
+    /// resolves is sent as TLS 1.3 0-RTT early data instead of waiting for
+    /// the full round trip.
+    #[cfg(feature = "early-data")]
+    fn early_data_connect_async<S>(&self, domain: DNSNameRef, stream: S)
+        -> ConnectAsync<S>
+        where S: AsyncRead + AsyncWrite;
 }
 
 /// Extension trait for the `Arc<ServerConfig>` type in the `rustls` crate.
@@ -34,6 +44,69 @@ pub trait ServerConfigExt {
 }
 
 
+/// A cloneable wrapper around a shared `ClientConfig`.
+#[derive(Clone)]
+pub struct TlsConnector {
+    inner: Arc<ClientConfig>,
+    #[cfg(feature = "early-data")]
+    early_data: bool
+}
+
+impl TlsConnector {
+    /// Connects to `domain` over `stream`, starting the TLS handshake.
+    pub fn connect<S>(&self, domain: DNSNameRef, stream: S) -> ConnectAsync<S>
+        where S: AsyncRead + AsyncWrite
+    {
+        #[cfg(feature = "early-data")] {
+            if self.early_data {
+                return self.inner.early_data_connect_async(domain, stream);
+            }
+        }
+
+        self.inner.connect_async(domain, stream)
+    }
+
+    /// Enables or disables sending TLS 1.3 0-RTT early data on connections
+    /// made through this connector.
+    #[cfg(feature = "early-data")]
+    pub fn early_data(mut self, enabled: bool) -> TlsConnector {
+        self.early_data = enabled;
+        self
+    }
+}
+
+impl From<Arc<ClientConfig>> for TlsConnector {
+    fn from(inner: Arc<ClientConfig>) -> TlsConnector {
+        TlsConnector {
+            inner,
+            #[cfg(feature = "early-data")]
+            early_data: false
+        }
+    }
+}
+
+/// A cloneable wrapper around a shared `ServerConfig`.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    inner: Arc<ServerConfig>
+}
+
+impl TlsAcceptor {
+    /// Accepts a TLS connection over `stream`, starting the handshake.
+    pub fn accept<S>(&self, stream: S) -> AcceptAsync<S>
+        where S: AsyncRead + AsyncWrite
+    {
+        self.inner.accept_async(stream)
+    }
+}
+
+impl From<Arc<ServerConfig>> for TlsAcceptor {
+    fn from(inner: Arc<ServerConfig>) -> TlsAcceptor {
+        TlsAcceptor { inner }
+    }
+}
+
+
 /// Future returned from `ClientConfigExt::connect_async` which will resolve
 /// once the connection handshake has finished.
 pub struct ConnectAsync<S>(MidHandshake<S, ClientSession>);
@@ -50,6 +123,16 @@ impl ClientConfigExt for Arc<ClientConfig> {
     {
         connect_async_with_session(stream, ClientSession::new(self, domain))
     }
+
+    #[cfg(feature = "early-data")]
+    fn early_data_connect_async<S>(&self, domain: DNSNameRef, stream: S)
+        -> ConnectAsync<S>
+        where S: AsyncRead + AsyncWrite
+    {
+        ConnectAsync(MidHandshake {
+            inner: Some(TlsStream::new_early_data(stream, ClientSession::new(self, domain)))
+        })
+    }
 }
 
 #[inline]
@@ -90,6 +173,23 @@ impl<S: AsyncRead + AsyncWrite> Future for ConnectAsync<S> {
     }
 }
 
+#[cfg(feature = "early-data")]
+impl<S: AsyncRead + AsyncWrite> ConnectAsync<S> {
+    /// Writes `buf` while the handshake is still in flight. If this future
+    /// came from `early_data_connect_async` and the session has an
+    /// early-data writer, `buf` is sent as TLS 1.3 early data alongside the
+    /// ClientHello instead of waiting for the future to resolve; otherwise
+    /// it is queued as ordinary application data and sent once the
+    /// handshake completes. Must be called before this future yields
+    /// `Async::Ready`.
+    pub fn write_early_data(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::Write::write(
+            self.0.inner.as_mut().expect("ConnectAsync already resolved"),
+            buf
+        )
+    }
+}
+
 impl<S: AsyncRead + AsyncWrite> Future for AcceptAsync<S> {
     type Item = TlsStream<S, ServerSession>;
     type Error = io::Error;
@@ -104,6 +204,7 @@ struct MidHandshake<S, C> {
     inner: Option<TlsStream<S, C>>
 }
 
+#[cfg(not(feature = "early-data"))]
 impl<S, C> Future for MidHandshake<S, C>
     where S: AsyncRead + AsyncWrite, C: Session
 {
@@ -133,6 +234,88 @@ impl<S, C> Future for MidHandshake<S, C>
     }
 }
 
+#[cfg(feature = "early-data")]
+impl<S, C> Future for MidHandshake<S, C>
+    where S: AsyncRead + AsyncWrite, C: Session + EarlyDataSession
+{
+    type Item = TlsStream<S, C>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let stream = self.inner.as_mut().unwrap();
+            if !stream.session.is_handshaking() { break };
+
+            match stream.do_io() {
+                Ok(()) => match (stream.eof, stream.session.is_handshaking()) {
+                    (true, true) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                    (false, true) => continue,
+                    (..) => break
+                },
+                Err(e) => match (e.kind(), stream.session.is_handshaking()) {
+                    (io::ErrorKind::WouldBlock, true) => return Ok(Async::NotReady),
+                    (io::ErrorKind::WouldBlock, false) => break,
+                    (..) => return Err(e)
+                }
+            }
+        }
+
+        // The handshake is done (or was never in progress); drain any
+        // early data that's still buffered now, rather than leaving it
+        // to be picked up as a side effect of the caller's next write.
+        let stream = self.inner.as_mut().unwrap();
+        stream.drain_early_data()?;
+
+        Ok(Async::Ready(self.inner.take().unwrap()))
+    }
+}
+
+
+/// Extends `rustls::Session` with the hooks needed to support optional TLS
+/// 1.3 0-RTT early data; a no-op for sessions (such as `ServerSession`) that
+/// have nothing to send as early data.
+#[cfg(feature = "early-data")]
+pub(crate) trait EarlyDataSession {
+    fn write_early_data(&mut self, buf: &[u8]) -> Option<io::Result<usize>>;
+    fn early_data_accepted(&self) -> bool;
+}
+
+#[cfg(feature = "early-data")]
+impl EarlyDataSession for ClientSession {
+    fn write_early_data(&mut self, buf: &[u8]) -> Option<io::Result<usize>> {
+        self.early_data().map(|mut w| io::Write::write(&mut w, buf))
+    }
+
+    fn early_data_accepted(&self) -> bool {
+        self.is_early_data_accepted()
+    }
+}
+
+#[cfg(feature = "early-data")]
+impl EarlyDataSession for ServerSession {
+    fn write_early_data(&mut self, _buf: &[u8]) -> Option<io::Result<usize>> {
+        None
+    }
+
+    fn early_data_accepted(&self) -> bool {
+        false
+    }
+}
+
+/// Write-side state of a `TlsStream`, tracked separately from the
+/// handshake so early data can be buffered until the server's acceptance
+/// of it is known.
+#[cfg(feature = "early-data")]
+#[derive(Debug)]
+enum TlsState {
+    /// Ordinary record-layer behaviour: writes go straight through
+    /// `Session::write`.
+    Stream,
+    /// Buffering writes as 0-RTT early data until the handshake resolves.
+    /// `buffered` mirrors every byte handed to `write` so it can be
+    /// replayed on the 1-RTT connection if the server rejects early data.
+    EarlyData { sent: usize, buffered: Vec<u8> }
+}
 
 /// A wrapper around an underlying raw stream which implements the TLS or SSL
 /// protocol.
@@ -141,7 +324,9 @@ pub struct TlsStream<S, C> {
     is_shutdown: bool,
     eof: bool,
     io: S,
-    session: C
+    session: C,
+    #[cfg(feature = "early-data")]
+    state: TlsState
 }
 
 impl<S, C> TlsStream<S, C> {
@@ -154,6 +339,27 @@ impl<S, C> TlsStream<S, C> {
     }
 }
 
+impl<S, C: Session> TlsStream<S, C> {
+    /// Returns the ALPN protocol negotiated during the handshake, if any.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.session.get_alpn_protocol()
+    }
+
+    /// Returns the TLS protocol version negotiated during the handshake,
+    /// once it has completed.
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        self.session.get_protocol_version()
+    }
+}
+
+impl<S> TlsStream<S, ServerSession> {
+    /// Returns the certificate chain presented by the peer, if the server
+    /// was configured to request client authentication.
+    pub fn peer_certificates(&self) -> Option<Vec<Certificate>> {
+        self.session.get_peer_certificates()
+    }
+}
+
 impl<S, C> TlsStream<S, C>
     where S: AsyncRead + AsyncWrite, C: Session
 {
@@ -162,8 +368,10 @@ impl<S, C> TlsStream<S, C>
         TlsStream {
             is_shutdown: false,
             eof: false,
-            io: io,
-            session: session
+            io,
+            session,
+            #[cfg(feature = "early-data")]
+            state: TlsState::Stream
         }
     }
 
@@ -184,7 +392,7 @@ impl<S, C> TlsStream<S, C>
                                 // ignore result to avoid masking original error
                                 let _ = self.session.write_tls(&mut self.io);
                             }
-                            return Err(io::Error::new(io::ErrorKind::Other, err));
+                            return Err(io::Error::other(err));
                         }
                         continue
                     },
@@ -214,6 +422,50 @@ impl<S, C> TlsStream<S, C>
     }
 }
 
+#[cfg(feature = "early-data")]
+#[allow(private_bounds)] // `new_early_data` itself is crate-private
+impl<S, C> TlsStream<S, C>
+    where S: AsyncRead + AsyncWrite, C: Session + EarlyDataSession
+{
+    #[inline]
+    fn new_early_data(io: S, session: C) -> TlsStream<S, C> {
+        TlsStream {
+            is_shutdown: false,
+            eof: false,
+            io,
+            session,
+            state: TlsState::EarlyData { sent: 0, buffered: Vec::new() }
+        }
+    }
+
+    /// If the handshake has finished while we were still buffering early
+    /// data, replays whatever didn't make it across as 0-RTT data (all of
+    /// it if the server rejected early data outright, only the tail past
+    /// `sent` if it was accepted but capped by `max_early_data_size`) and
+    /// switches to ordinary stream behaviour. A no-op once that's done, so
+    /// it's safe to call from every path that can observe the handshake
+    /// completing.
+    fn drain_early_data(&mut self) -> io::Result<()> {
+        if self.session.is_handshaking() {
+            return Ok(());
+        }
+
+        if let TlsState::EarlyData { ref mut sent, ref mut buffered } = self.state {
+            let all = mem::take(buffered);
+            let sent = *sent;
+            let accepted = self.session.early_data_accepted();
+            self.state = TlsState::Stream;
+
+            let unsent = if accepted { &all[sent..] } else { &all[..] };
+            if !unsent.is_empty() {
+                io::Write::write_all(self, unsent)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
 impl<S, C> io::Read for TlsStream<S, C>
     where S: AsyncRead + AsyncWrite, C: Session
 {
@@ -233,6 +485,7 @@ impl<S, C> io::Read for TlsStream<S, C>
     }
 }
 
+#[cfg(not(feature = "early-data"))]
 impl<S, C> io::Write for TlsStream<S, C>
     where S: AsyncRead + AsyncWrite, C: Session
 {
@@ -273,12 +526,86 @@ impl<S, C> io::Write for TlsStream<S, C>
     }
 }
 
+#[cfg(feature = "early-data")]
+impl<S, C> io::Write for TlsStream<S, C>
+    where S: AsyncRead + AsyncWrite, C: Session + EarlyDataSession
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // In case the handshake resolved since the last call and nothing
+        // has drained the buffered early data yet.
+        self.drain_early_data()?;
+
+        if let TlsState::EarlyData { ref mut sent, ref mut buffered } = self.state {
+            buffered.extend_from_slice(buf);
+
+            while self.session.wants_write() {
+                match self.session.write_tls(&mut self.io) {
+                    Ok(_) => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e)
+                }
+            }
+
+            return match self.session.write_early_data(&buffered[*sent..]) {
+                Some(Ok(n)) => { *sent += n; Ok(buf.len()) },
+                Some(Err(ref e)) if e.kind() == io::ErrorKind::WouldBlock => Ok(buf.len()),
+                Some(Err(e)) => Err(e),
+                // The session has no early-data writer (e.g. no resumable
+                // ticket); send everything buffered so far as an ordinary
+                // write instead of silently dropping it.
+                None => {
+                    let all = mem::take(buffered);
+                    self.state = TlsState::Stream;
+                    io::Write::write_all(self, &all)?;
+                    Ok(buf.len())
+                }
+            };
+        }
+
+        loop {
+            let output = self.session.write(buf)?;
+
+            while self.session.wants_write() {
+                match self.session.write_tls(&mut self.io) {
+                    Ok(_) => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => if output == 0 {
+                        // Both rustls buffer and IO buffer are blocking.
+                        return Err(io::Error::from(io::ErrorKind::WouldBlock));
+                    } else {
+                        break;
+                    },
+                    Err(e) => return Err(e)
+                }
+            }
+
+            if output > 0 {
+                // Already wrote something out.
+                return Ok(output);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain_early_data()?;
+        self.session.flush()?;
+        while self.session.wants_write() {
+            self.session.write_tls(&mut self.io)?;
+        }
+        self.io.flush()
+    }
+}
+
 impl<S, C> AsyncRead for TlsStream<S, C>
     where
         S: AsyncRead + AsyncWrite,
         C: Session
 {}
 
+#[cfg(not(feature = "early-data"))]
 impl<S, C> AsyncWrite for TlsStream<S, C>
     where
         S: AsyncRead + AsyncWrite,
@@ -296,3 +623,331 @@ impl<S, C> AsyncWrite for TlsStream<S, C>
         self.io.shutdown()
     }
 }
+
+#[cfg(feature = "early-data")]
+impl<S, C> AsyncWrite for TlsStream<S, C>
+    where
+        S: AsyncRead + AsyncWrite,
+        C: Session + EarlyDataSession
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        try_nb!(self.drain_early_data());
+        if !self.is_shutdown {
+            self.session.send_close_notify();
+            self.is_shutdown = true;
+        }
+        while self.session.wants_write() {
+            try_nb!(self.session.write_tls(&mut self.io));
+        }
+        try_nb!(self.io.flush());
+        self.io.shutdown()
+    }
+}
+
+#[cfg(unix)]
+impl<S, C> ::std::os::unix::io::AsRawFd for TlsStream<S, C>
+    where S: ::std::os::unix::io::AsRawFd
+{
+    fn as_raw_fd(&self) -> ::std::os::unix::io::RawFd {
+        self.io.as_raw_fd()
+    }
+}
+
+#[cfg(windows)]
+impl<S, C> ::std::os::windows::io::AsRawSocket for TlsStream<S, C>
+    where S: ::std::os::windows::io::AsRawSocket
+{
+    fn as_raw_socket(&self) -> ::std::os::windows::io::RawSocket {
+        self.io.as_raw_socket()
+    }
+}
+
+
+/// A stream that is either plaintext or wrapped in server-side TLS.
+#[derive(Debug)]
+pub enum MaybeTlsStream<S> {
+    Plain(S),
+    Tls(Box<TlsStream<S, ServerSession>>)
+}
+
+/// Future returned by `accept_maybe_tls_async` which will resolve once any
+/// TLS handshake (if one is attempted at all) has finished.
+pub enum MaybeAccept<S> {
+    Plain(Option<S>),
+    Tls(Box<AcceptAsync<S>>)
+}
+
+/// Accepts `stream` as TLS using `config` if one is given, otherwise
+/// yields the plaintext stream directly.
+#[inline]
+pub fn accept_maybe_tls_async<S>(stream: S, config: Option<Arc<ServerConfig>>)
+    -> MaybeAccept<S>
+    where S: AsyncRead + AsyncWrite
+{
+    match config {
+        Some(config) => MaybeAccept::Tls(Box::new(config.accept_async(stream))),
+        None => MaybeAccept::Plain(Some(stream))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Future for MaybeAccept<S> {
+    type Item = MaybeTlsStream<S>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match *self {
+            MaybeAccept::Plain(ref mut stream) =>
+                Ok(Async::Ready(MaybeTlsStream::Plain(stream.take().unwrap()))),
+            MaybeAccept::Tls(ref mut accept) => match accept.poll()? {
+                Async::Ready(stream) => Ok(Async::Ready(MaybeTlsStream::Tls(Box::new(stream)))),
+                Async::NotReady => Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+impl<S> From<TlsStream<S, ServerSession>> for MaybeTlsStream<S> {
+    fn from(stream: TlsStream<S, ServerSession>) -> Self {
+        MaybeTlsStream::Tls(Box::new(stream))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> io::Read for MaybeTlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            MaybeTlsStream::Plain(ref mut stream) => stream.read(buf),
+            MaybeTlsStream::Tls(ref mut stream) => stream.read(buf)
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> io::Write for MaybeTlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            MaybeTlsStream::Plain(ref mut stream) => stream.write(buf),
+            MaybeTlsStream::Tls(ref mut stream) => stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            MaybeTlsStream::Plain(ref mut stream) => stream.flush(),
+            MaybeTlsStream::Tls(ref mut stream) => stream.flush()
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for MaybeTlsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for MaybeTlsStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            MaybeTlsStream::Plain(ref mut stream) => stream.shutdown(),
+            MaybeTlsStream::Tls(ref mut stream) => stream.shutdown()
+        }
+    }
+}
+
+
+/// Client-side counterpart to `MaybeTlsStream`.
+#[derive(Debug)]
+pub enum MaybeClientTlsStream<S> {
+    Plain(S),
+    Tls(Box<TlsStream<S, ClientSession>>)
+}
+
+impl<S> From<TlsStream<S, ClientSession>> for MaybeClientTlsStream<S> {
+    fn from(stream: TlsStream<S, ClientSession>) -> Self {
+        MaybeClientTlsStream::Tls(Box::new(stream))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> io::Read for MaybeClientTlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            MaybeClientTlsStream::Plain(ref mut stream) => stream.read(buf),
+            MaybeClientTlsStream::Tls(ref mut stream) => stream.read(buf)
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> io::Write for MaybeClientTlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            MaybeClientTlsStream::Plain(ref mut stream) => stream.write(buf),
+            MaybeClientTlsStream::Tls(ref mut stream) => stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            MaybeClientTlsStream::Plain(ref mut stream) => stream.flush(),
+            MaybeClientTlsStream::Tls(ref mut stream) => stream.flush()
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for MaybeClientTlsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for MaybeClientTlsStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            MaybeClientTlsStream::Plain(ref mut stream) => stream.shutdown(),
+            MaybeClientTlsStream::Tls(ref mut stream) => stream.shutdown()
+        }
+    }
+}
+
+#[cfg(all(test, feature = "early-data"))]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io::{ BufReader, Read };
+    use std::rc::Rc;
+    use rustls::{ NoClientAuth, RootCertStore, ServerCertVerified, TLSError };
+    use rustls::internal::pemfile::{ certs, pkcs8_private_keys };
+
+    // A throwaway self-signed ECDSA cert/key for "localhost", generated with
+    // `openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 ...`.
+    // Only used to get a real handshake going; `NoVerifier` below means the
+    // client never actually checks it against anything.
+    const TEST_CERT: &str = include_str!("../tests/data/test_cert.pem");
+    const TEST_KEY: &str = include_str!("../tests/data/test_key.pem");
+
+    /// Accepts any certificate, so the test can drive a real handshake
+    /// without a trusted CA chain. Gated on rustls's own
+    /// `dangerous_configuration` feature, which this crate only enables as a
+    /// dev-dependency, so it never affects non-test builds.
+    struct NoVerifier;
+
+    impl rustls::ServerCertVerifier for NoVerifier {
+        fn verify_server_cert(&self, _roots: &RootCertStore, _presented_certs: &[Certificate],
+            _dns_name: DNSNameRef, _ocsp_response: &[u8]) -> Result<ServerCertVerified, TLSError>
+        {
+            Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    /// One end of an in-memory duplex pipe: reads drain `read`, writes go to
+    /// `write`. Pairing two `Pipe`s with the buffers swapped connects a
+    /// client and server transport without any real sockets.
+    #[derive(Clone)]
+    struct Pipe {
+        read: Rc<RefCell<VecDeque<u8>>>,
+        write: Rc<RefCell<VecDeque<u8>>>
+    }
+
+    fn pipe_pair() -> (Pipe, Pipe) {
+        let a = Rc::new(RefCell::new(VecDeque::new()));
+        let b = Rc::new(RefCell::new(VecDeque::new()));
+        (Pipe { read: a.clone(), write: b.clone() }, Pipe { read: b, write: a })
+    }
+
+    impl io::Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut r = self.read.borrow_mut();
+            if r.is_empty() {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+            let n = buf.len().min(r.len());
+            for slot in buf[..n].iter_mut() {
+                *slot = r.pop_front().unwrap();
+            }
+            Ok(n)
+        }
+    }
+
+    impl io::Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write.borrow_mut().extend(buf.iter().cloned());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    impl AsyncRead for Pipe {}
+    impl AsyncWrite for Pipe {
+        fn shutdown(&mut self) -> Poll<(), io::Error> { Ok(Async::Ready(())) }
+    }
+
+    fn test_configs() -> (Arc<ClientConfig>, Arc<ServerConfig>) {
+        let cert_chain = certs(&mut BufReader::new(TEST_CERT.as_bytes())).unwrap();
+        let mut keys = pkcs8_private_keys(&mut BufReader::new(TEST_KEY.as_bytes())).unwrap();
+
+        let mut server_config = ServerConfig::new(NoClientAuth::new());
+        server_config.set_single_cert(cert_chain, keys.remove(0)).unwrap();
+
+        let mut client_config = ClientConfig::new();
+        client_config.dangerous().set_certificate_verifier(Arc::new(NoVerifier));
+
+        (Arc::new(client_config), Arc::new(server_config))
+    }
+
+    /// Drives both ends of a handshake to completion by polling them in
+    /// lockstep. Neither side ever truly blocks on this in-memory transport
+    /// (a `WouldBlock` just means "nothing for you yet"), so alternating
+    /// polls always makes progress until both resolve.
+    fn handshake(mut client: ConnectAsync<Pipe>, mut server: AcceptAsync<Pipe>)
+        -> (TlsStream<Pipe, ClientSession>, TlsStream<Pipe, ServerSession>)
+    {
+        let mut client_done = None;
+        let mut server_done = None;
+
+        for _ in 0..10_000 {
+            if client_done.is_none() {
+                if let Async::Ready(stream) = client.poll().unwrap() {
+                    client_done = Some(stream);
+                }
+            }
+            if server_done.is_none() {
+                if let Async::Ready(stream) = server.poll().unwrap() {
+                    server_done = Some(stream);
+                }
+            }
+            if let Some(client) = client_done.take() {
+                if let Some(server) = server_done.take() {
+                    return (client, server);
+                }
+                client_done = Some(client);
+            }
+        }
+
+        panic!("handshake did not converge");
+    }
+
+    #[test]
+    fn rejected_early_data_is_replayed_as_ordinary_writes() {
+        let (client_io, server_io) = pipe_pair();
+        let (client_config, server_config) = test_configs();
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+
+        let mut connect = client_config.early_data_connect_async(domain, client_io);
+        let accept = server_config.accept_async(server_io);
+
+        // A fresh (non-resumed) `ClientSession` never actually offers 0-RTT
+        // in this rustls version (it's only implemented over QUIC), so
+        // `write_early_data` would never return `Some` here. Set up the
+        // scenario directly instead: bytes are sitting in `TlsState::EarlyData`
+        // as if an earlier `write_early_data` call had buffered them, exactly
+        // as `ConnectAsync::write_early_data`'s documented usage does, and
+        // the server goes on to reject early data as real servers do.
+        {
+            let stream = connect.0.inner.as_mut().unwrap();
+            stream.state = TlsState::EarlyData {
+                sent: 0,
+                buffered: b"request while mid-handshake".to_vec()
+            };
+        }
+
+        // Without ever writing again, the handshake is driven to completion
+        // -- so only `MidHandshake::poll`, which drains `self.state` once the
+        // handshake is done, can get the buffered bytes to the server.
+        let (_client, mut server) = handshake(connect, accept);
+
+        let mut received = vec![0u8; b"request while mid-handshake".len()];
+        server.read_exact(&mut received).unwrap();
+        assert_eq!(&received[..], &b"request while mid-handshake"[..]);
+    }
+}